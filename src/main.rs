@@ -1,8 +1,10 @@
 #[macro_use]
 extern crate quicli;
 use quicli::prelude::*;
+use std::fmt;
+use std::fs::File;
 use std::io;
-use std::io::Read;
+use std::io::{BufWriter, Read, Write};
 
 #[derive(Debug, StructOpt)]
 struct Cli {
@@ -31,6 +33,14 @@ struct Cli {
     /// The file to read from, or - for stdin
     file: String,
 
+    /** file to use as runtime input for the `,` instruction
+
+    defaults to stdin, except when `file` is itself "-", in which case
+    stdin is already consumed by the program source and this becomes required
+    */
+    #[structopt(long = "stdin")]
+    input: Option<String>,
+
     /** runtime mode, which can be one of:
     d    dump parsed code
     r    run normally
@@ -56,6 +66,13 @@ struct Cli {
     verbosity: Verbosity,
 }
 
+#[derive(Clone, Copy)]
+enum Direction {
+    Forward,
+    Backward,
+}
+
+#[derive(Clone, Copy)]
 enum Instruction {
     OpVinc { quantity: usize },
     OpVdec { quantity: usize },
@@ -65,6 +82,10 @@ enum Instruction {
     OpOut { quantity: usize },
     OpLstart { destination: usize },
     OpLend { destination: usize },
+    // collapsed loop idioms, produced by `optimize`
+    OpClear,
+    OpScan { stride: usize, dir: Direction },
+    OpMulAdd { offset: isize, factor: isize },
 }
 
 impl Instruction {
@@ -88,10 +109,67 @@ fn variant_eq<T>(a: &T, b: &T) -> bool {
     std::mem::discriminant(a) == std::mem::discriminant(b)
 }
 
-fn preprocess(instructions: &mut Vec<Instruction>, buffer: &String) {
+// `OpLstart`/`OpLend` must never be run-length merged: each one is tied to a
+// distinct bracket and carries its own jump `destination`, unlike the other
+// ops where adjacent same-kind instructions are interchangeable.
+fn mergeable(instruction: &Instruction) -> bool {
+    !matches!(
+        instruction,
+        Instruction::OpLstart { .. } | Instruction::OpLend { .. }
+    )
+}
+
+// Structured replacement for the panics that used to come out of parsing and
+// execution, so quicli can surface a tidy diagnostic instead of unwinding.
+#[derive(Debug)]
+enum WobooError {
+    UnmatchedLoopClose { pos: usize },
+    UnmatchedLoopOpen { pos: usize },
+    ValueOverflow { pos: usize },
+    PointerOutOfBounds { pos: usize },
+    InputError(io::Error),
+    OutputError(io::Error),
+    StdinConflict,
+}
+
+impl fmt::Display for WobooError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WobooError::UnmatchedLoopClose { pos } => {
+                write!(f, "unmatched `]` operator at source offset {}", pos)
+            }
+            WobooError::UnmatchedLoopOpen { pos } => {
+                write!(f, "unmatched `[` operator at source offset {}", pos)
+            }
+            WobooError::ValueOverflow { pos } => {
+                write!(f, "cell value overflowed at instruction {}", pos)
+            }
+            WobooError::PointerOutOfBounds { pos } => {
+                write!(f, "cell pointer went out of bounds at instruction {}", pos)
+            }
+            WobooError::InputError(err) => write!(f, "failed to read runtime input: {}", err),
+            WobooError::OutputError(err) => write!(f, "failed to write output: {}", err),
+            WobooError::StdinConflict => write!(
+                f,
+                "reading the program from stdin requires --stdin <file> for runtime input"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WobooError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WobooError::InputError(err) | WobooError::OutputError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+fn preprocess(instructions: &mut Vec<Instruction>, buffer: &String) -> Result<(), WobooError> {
     let mut index = 0;
-    let mut stack: Vec<usize> = Vec::new();
-    for c in buffer.chars() {
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+    for (pos, c) in buffer.chars().enumerate() {
         match c {
             // FIXME: Wrap
             '+' => instructions.push(Instruction::OpVinc { quantity: 1 }),
@@ -99,14 +177,15 @@ fn preprocess(instructions: &mut Vec<Instruction>, buffer: &String) {
             '>' => instructions.push(Instruction::OpPinc { quantity: 1 }),
             '<' => instructions.push(Instruction::OpPdec { quantity: 1 }),
             '.' => instructions.push(Instruction::OpOut { quantity: 1 }),
+            ',' => instructions.push(Instruction::OpIn { quantity: 1 }),
             '[' => {
-                stack.push(instructions.len());
+                stack.push((pos, instructions.len()));
                 instructions.push(Instruction::OpLstart { destination: 0 });
             }
             ']' => {
-                let dest = match stack.pop() {
-                    Some(dest) => dest,
-                    _ => panic!("Unmatched ] operator"),
+                let (_, dest) = match stack.pop() {
+                    Some(entry) => entry,
+                    None => return Err(WobooError::UnmatchedLoopClose { pos }),
                 };
                 instructions[dest] = Instruction::OpLstart {
                     destination: instructions.len(),
@@ -115,59 +194,388 @@ fn preprocess(instructions: &mut Vec<Instruction>, buffer: &String) {
             }
             _ => continue, // comments or newline
         }
-        if index > 0 {
+        if index > 0
+            && variant_eq(&instructions[index - 1], &instructions[index])
+            && mergeable(&instructions[index])
+        {
             // group nearby together
-            if variant_eq(&instructions[index - 1], &instructions[index]) {
-                instructions.pop(); // remove the newest
-                instructions[index - 1].inc();
-            } else {
-                index += 1;
-            }
+            instructions.pop(); // remove the newest
+            instructions[index - 1].inc();
         } else {
             index += 1;
         }
     }
-    if stack.len() > 0 {
-        panic!("Not enough ] operators!");
+    if let Some((pos, _)) = stack.first() {
+        return Err(WobooError::UnmatchedLoopOpen { pos: *pos });
+    }
+    Ok(())
+}
+
+// Recognizes a `[ ... ]` body as one of three common idioms and lowers it to
+// dedicated opcodes: a single odd value step clears the cell, a single
+// pointer step scans for a zero cell, and a balanced body that decrements the
+// loop cell by exactly one per iteration is a multiply-add. Bodies holding a
+// nested loop or I/O never match. Returns `None` if nothing matches, leaving
+// the loop to run as written.
+fn try_optimize_body(body: &[Instruction]) -> Option<Vec<Instruction>> {
+    if body.len() == 1 {
+        match &body[0] {
+            Instruction::OpVdec { quantity } | Instruction::OpVinc { quantity }
+                if quantity % 2 == 1 =>
+            {
+                return Some(vec![Instruction::OpClear]);
+            }
+            Instruction::OpPinc { quantity } => {
+                return Some(vec![Instruction::OpScan {
+                    stride: *quantity,
+                    dir: Direction::Forward,
+                }]);
+            }
+            Instruction::OpPdec { quantity } => {
+                return Some(vec![Instruction::OpScan {
+                    stride: *quantity,
+                    dir: Direction::Backward,
+                }]);
+            }
+            _ => {}
+        }
+    }
+
+    if body
+        .iter()
+        .any(|instr| matches!(instr, Instruction::OpOut { .. } | Instruction::OpIn { .. }))
+    {
+        return None;
+    }
+
+    let mut offset: isize = 0;
+    let mut deltas: Vec<(isize, isize)> = Vec::new();
+    for instr in body {
+        let (at, amount) = match instr {
+            Instruction::OpVinc { quantity } => (offset, *quantity as isize),
+            Instruction::OpVdec { quantity } => (offset, -(*quantity as isize)),
+            Instruction::OpPinc { quantity } => {
+                offset += *quantity as isize;
+                continue;
+            }
+            Instruction::OpPdec { quantity } => {
+                offset -= *quantity as isize;
+                continue;
+            }
+            _ => return None,
+        };
+        match deltas.iter_mut().find(|(o, _)| *o == at) {
+            Some((_, total)) => *total += amount,
+            None => deltas.push((at, amount)),
+        }
+    }
+
+    if offset != 0 || deltas.iter().find(|(o, _)| *o == 0).map(|(_, v)| *v) != Some(-1) {
+        return None;
+    }
+
+    let mut ops: Vec<Instruction> = deltas
+        .into_iter()
+        .filter(|(at, factor)| *at != 0 && *factor != 0)
+        .map(|(offset, factor)| Instruction::OpMulAdd { offset, factor })
+        .collect();
+    ops.push(Instruction::OpClear);
+    Some(ops)
+}
+
+// Post-pass over the run-length-merged instructions: collapses clear/scan/
+// multiply-add loop idioms into dedicated opcodes, then recomputes the jump
+// destinations of whatever loops remain.
+fn optimize(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut output: Vec<Instruction> = Vec::with_capacity(instructions.len());
+    let mut old_to_new: Vec<usize> = vec![0; instructions.len()];
+    let mut i = 0;
+    while i < instructions.len() {
+        if let Instruction::OpLstart { destination: end } = instructions[i] {
+            let body = &instructions[i + 1..end];
+            if let Some(replacement) = try_optimize_body(body) {
+                old_to_new[i] = output.len();
+                output.extend(replacement);
+                i = end + 1;
+                continue;
+            }
+        }
+        old_to_new[i] = output.len();
+        output.push(instructions[i]);
+        i += 1;
+    }
+
+    for instruction in output.iter_mut() {
+        match instruction {
+            Instruction::OpLstart { destination } | Instruction::OpLend { destination } => {
+                *destination = old_to_new[*destination];
+            }
+            _ => {}
+        }
+    }
+
+    output
+}
+
+// Renders a listing like a small bytecode disassembler: one line per
+// instruction with its index, mnemonic, run-length quantity, and the
+// resolved jump destination for loop ops.
+fn dump(instructions: &Vec<Instruction>) {
+    for (index, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            Instruction::OpVinc { quantity } => println!("{:>6}  VINC    {}", index, quantity),
+            Instruction::OpVdec { quantity } => println!("{:>6}  VDEC    {}", index, quantity),
+            Instruction::OpPinc { quantity } => println!("{:>6}  PINC    {}", index, quantity),
+            Instruction::OpPdec { quantity } => println!("{:>6}  PDEC    {}", index, quantity),
+            Instruction::OpIn { quantity } => println!("{:>6}  IN      {}", index, quantity),
+            Instruction::OpOut { quantity } => println!("{:>6}  OUT     {}", index, quantity),
+            Instruction::OpLstart { destination } => {
+                println!("{:>6}  LSTART  -> {}", index, destination)
+            }
+            Instruction::OpLend { destination } => {
+                println!("{:>6}  LEND    -> {}", index, destination)
+            }
+            Instruction::OpClear => println!("{:>6}  CLEAR", index),
+            Instruction::OpScan { stride, dir } => {
+                let dir = match dir {
+                    Direction::Forward => "fwd",
+                    Direction::Backward => "bwd",
+                };
+                println!("{:>6}  SCAN    {} {}", index, dir, stride)
+            }
+            Instruction::OpMulAdd { offset, factor } => {
+                println!("{:>6}  MULADD  [{:+}] += [0] * {}", index, offset, factor)
+            }
+        }
+    }
+}
+
+// Dispatches on an overflow/underflow mode given the `(value, overflowed)`
+// pair produced by a checked arithmetic helper, so each arithmetic site in
+// `execute` is a single guarded expression. `$clamp` is the value used in "i"
+// mode. Value ops pass the boundary they were heading towards (e.g. `maximum`
+// for an increment): since ops are run-length-grouped, landing on that
+// boundary keeps whatever progress was made before it was hit. Pointer ops
+// pass the current pointer, so an out-of-bounds move leaves it unchanged.
+macro_rules! overflowing {
+    ($pair:expr, $mode:expr, $clamp:expr, $err:expr) => {{
+        let (value, overflowed) = $pair;
+        if overflowed {
+            match $mode.as_str() {
+                "i" => $clamp,
+                "e" => return Err($err),
+                _ => value, // "w": wrap
+            }
+        } else {
+            value
+        }
+    }};
+}
+
+// Lets cell storage width follow the configured `-a`/`-b` range instead of
+// being hardcoded to `u8`: `main!` picks `u8`, `u16` or `u32` depending on
+// the configured maximum, and `execute` is generic over the chosen width.
+trait Cell: Copy {
+    fn to_u64(self) -> u64;
+    fn from_u64(value: u64) -> Self;
+}
+
+impl Cell for u8 {
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+    fn from_u64(value: u64) -> Self {
+        value as u8
+    }
+}
+
+impl Cell for u16 {
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+    fn from_u64(value: u64) -> Self {
+        value as u16
+    }
+}
+
+impl Cell for u32 {
+    fn to_u64(self) -> u64 {
+        self as u64
     }
+    fn from_u64(value: u64) -> Self {
+        value as u32
+    }
+}
+
+fn add_within_range<T: Cell>(value: T, delta: u64, minimum: u64, maximum: u64) -> (T, bool) {
+    let range = maximum - minimum + 1;
+    let offset = (value.to_u64() - minimum) + delta;
+    (T::from_u64((offset % range) + minimum), offset >= range)
+}
+
+fn sub_within_range<T: Cell>(value: T, delta: u64, minimum: u64, maximum: u64) -> (T, bool) {
+    let range = (maximum - minimum + 1) as i64;
+    let offset = (value.to_u64() as i64 - minimum as i64) - delta as i64;
+    let wrapped = ((offset % range) + range) % range;
+    (T::from_u64((wrapped + minimum as i64) as u64), offset < 0)
 }
 
-fn execute(
+fn inc_pointer(ptr: usize, delta: usize, len: usize) -> (usize, bool) {
+    let sum = ptr + delta;
+    (sum % len, sum >= len)
+}
+
+fn dec_pointer(ptr: usize, delta: usize, len: usize) -> (usize, bool) {
+    if delta <= ptr {
+        (ptr - delta, false)
+    } else {
+        let remainder = (delta - ptr) % len;
+        let wrapped = if remainder == 0 { 0 } else { len - remainder };
+        (wrapped, true)
+    }
+}
+
+fn execute<T: Cell>(
     instructions: &Vec<Instruction>,
-    cells: &mut Vec<u8>,
+    cells: &mut Vec<T>,
     cell_index: usize,
     instruction_index: usize,
-) {
-    if instruction_index > instructions.len() - 1 {
-        return;
-    }
-    let instruction = &instructions[instruction_index];
-    let mut next_iindex = instruction_index + 1;
-    let mut next_cindex = cell_index;
-    match instruction {
-        Instruction::OpVinc { quantity } => cells[cell_index] += *quantity as u8,
-        Instruction::OpVdec { quantity } => cells[cell_index] -= *quantity as u8,
-        Instruction::OpPinc { quantity } => next_cindex += *quantity,
-        Instruction::OpPdec { quantity } => next_cindex -= *quantity,
-        Instruction::OpOut { quantity } => for _ in 0..*quantity {
-            print!("{}", cells[cell_index] as char)
-        },
-        Instruction::OpLstart { destination } => if cells[cell_index] == 0 {
-            next_iindex = *destination;
-        },
-        Instruction::OpLend { destination } => if cells[cell_index] > 0 {
-            next_iindex = *destination;
-        },
-        _ => (),
-    }
-
-    return execute(instructions, cells, next_cindex, next_iindex);
+    minimum: u64,
+    maximum: u64,
+    value_behaviour: &String,
+    pointer_behaviour: &String,
+    eof_value: &String,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<(), WobooError> {
+    let mut pc = instruction_index;
+    let mut ptr = cell_index;
+    let len = cells.len();
+
+    loop {
+        if pc >= instructions.len() {
+            break;
+        }
+        let instruction = &instructions[pc];
+        let mut next_pc = pc + 1;
+        match instruction {
+            Instruction::OpVinc { quantity } => {
+                let pair = add_within_range(cells[ptr], *quantity as u64, minimum, maximum);
+                cells[ptr] = overflowing!(
+                    pair,
+                    value_behaviour,
+                    T::from_u64(maximum),
+                    WobooError::ValueOverflow { pos: pc }
+                );
+            }
+            Instruction::OpVdec { quantity } => {
+                let pair = sub_within_range(cells[ptr], *quantity as u64, minimum, maximum);
+                cells[ptr] = overflowing!(
+                    pair,
+                    value_behaviour,
+                    T::from_u64(minimum),
+                    WobooError::ValueOverflow { pos: pc }
+                );
+            }
+            Instruction::OpPinc { quantity } => {
+                let pair = inc_pointer(ptr, *quantity, len);
+                ptr = overflowing!(
+                    pair,
+                    pointer_behaviour,
+                    ptr,
+                    WobooError::PointerOutOfBounds { pos: pc }
+                );
+            }
+            Instruction::OpPdec { quantity } => {
+                let pair = dec_pointer(ptr, *quantity, len);
+                ptr = overflowing!(
+                    pair,
+                    pointer_behaviour,
+                    ptr,
+                    WobooError::PointerOutOfBounds { pos: pc }
+                );
+            }
+            Instruction::OpOut { quantity } => {
+                let byte = cells[ptr].to_u64() as u8;
+                for _ in 0..*quantity {
+                    output.write_all(&[byte]).map_err(WobooError::OutputError)?;
+                }
+            }
+            Instruction::OpIn { quantity } => for _ in 0..*quantity {
+                let mut byte = [0u8; 1];
+                let read = input.read(&mut byte).map_err(WobooError::InputError)?;
+                cells[ptr] = if read == 0 {
+                    match eof_value.as_str() {
+                        "0" => T::from_u64(0),
+                        "a" => T::from_u64(minimum),
+                        "b" => T::from_u64(maximum),
+                        "n" => T::from_u64(maximum),
+                        "x" => cells[ptr],
+                        _ => cells[ptr],
+                    }
+                } else {
+                    T::from_u64(byte[0] as u64)
+                };
+            },
+            Instruction::OpLstart { destination } => if cells[ptr].to_u64() == 0 {
+                next_pc = *destination;
+            },
+            Instruction::OpLend { destination } => if cells[ptr].to_u64() > 0 {
+                next_pc = *destination;
+            },
+            Instruction::OpClear => cells[ptr] = T::from_u64(minimum),
+            Instruction::OpScan { stride, dir } => while cells[ptr].to_u64() != 0 {
+                let pair = match dir {
+                    Direction::Forward => inc_pointer(ptr, *stride, len),
+                    Direction::Backward => dec_pointer(ptr, *stride, len),
+                };
+                ptr = overflowing!(
+                    pair,
+                    pointer_behaviour,
+                    ptr,
+                    WobooError::PointerOutOfBounds { pos: pc }
+                );
+            },
+            Instruction::OpMulAdd { offset, factor } => {
+                let pair = if *offset >= 0 {
+                    inc_pointer(ptr, *offset as usize, len)
+                } else {
+                    dec_pointer(ptr, (-offset) as usize, len)
+                };
+                let target = overflowing!(
+                    pair,
+                    pointer_behaviour,
+                    ptr,
+                    WobooError::PointerOutOfBounds { pos: pc }
+                );
+                let magnitude = cells[ptr].to_u64() * (factor.abs() as u64);
+                let pair = if *factor >= 0 {
+                    add_within_range(cells[target], magnitude, minimum, maximum)
+                } else {
+                    sub_within_range(cells[target], magnitude, minimum, maximum)
+                };
+                cells[target] = overflowing!(
+                    pair,
+                    value_behaviour,
+                    if *factor >= 0 {
+                        T::from_u64(maximum)
+                    } else {
+                        T::from_u64(minimum)
+                    },
+                    WobooError::ValueOverflow { pos: pc }
+                );
+            }
+        }
+        pc = next_pc;
+    }
+    Ok(())
 }
 
 main!(|args: Cli, log_level: verbosity| {
     let mut buffer = String::new();
+    let program_from_stdin = args.file == "-";
 
-    if args.file == "-" {
+    if program_from_stdin {
         // Read from stdin
         io::stdin().read_to_string(&mut buffer)?;
     } else {
@@ -175,8 +583,75 @@ main!(|args: Cli, log_level: verbosity| {
     }
 
     let mut instructions: Vec<Instruction> = Vec::new();
-    preprocess(&mut instructions, &buffer);
-    // println!("{:?}", instructions);
-    let mut cells: Vec<u8> = vec![0; args.cells];
-    execute(&mut instructions, &mut cells, 0, 0);
+    preprocess(&mut instructions, &buffer)?;
+    let instructions = optimize(instructions);
+
+    if args.runtime == "d" {
+        dump(&instructions);
+        return Ok(());
+    }
+
+    let mut input: Box<dyn Read> = match args.input {
+        Some(path) => Box::new(File::open(path)?),
+        None if program_from_stdin => return Err(WobooError::StdinConflict.into()),
+        None => Box::new(io::stdin()),
+    };
+
+    let minimum = args.minimum as u64;
+    let maximum = args.maximum as u64;
+    let stdout = io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+
+    // `execute`'s result is captured rather than `?`-propagated immediately so the
+    // BufWriter is always flushed, even when the run ends in a WobooError — otherwise
+    // whatever output was already buffered would be silently lost on the error path.
+    let result = if maximum <= u8::max_value() as u64 {
+        let mut cells: Vec<u8> = vec![u8::from_u64(minimum); args.cells];
+        execute(
+            &instructions,
+            &mut cells,
+            0,
+            0,
+            minimum,
+            maximum,
+            &args.value_behaviour,
+            &args.pointer_behaviour,
+            &args.eof_value,
+            &mut input,
+            &mut output,
+        )
+    } else if maximum <= u16::max_value() as u64 {
+        let mut cells: Vec<u16> = vec![u16::from_u64(minimum); args.cells];
+        execute(
+            &instructions,
+            &mut cells,
+            0,
+            0,
+            minimum,
+            maximum,
+            &args.value_behaviour,
+            &args.pointer_behaviour,
+            &args.eof_value,
+            &mut input,
+            &mut output,
+        )
+    } else {
+        let mut cells: Vec<u32> = vec![u32::from_u64(minimum); args.cells];
+        execute(
+            &instructions,
+            &mut cells,
+            0,
+            0,
+            minimum,
+            maximum,
+            &args.value_behaviour,
+            &args.pointer_behaviour,
+            &args.eof_value,
+            &mut input,
+            &mut output,
+        )
+    };
+
+    output.flush()?;
+    result?;
 });